@@ -27,19 +27,63 @@ pub use ic_cdk::export::candid::{
 use serde::de::{Deserializer, Error as _};
 use serde_bytes::ByteBuf;
 
+// NOTE: the secp256k1 account scheme below pulls in two new dependencies that
+// the crate manifest must declare before the workspace builds:
+//   k256 = { version = "0.10", features = ["ecdsa", "keccak256"] }  // recoverable ECDSA + address derivation
+//   sha3 = "0.9"                                                    // Keccak-256 for EVM address derivation
+
 // Type definitions start here.
 
 #[derive(PartialEq, Debug, Eq, Default, Clone)]
 /// A hash as used by the signature scheme.
 pub struct Hash(pub digest::Output<Hasher>);
 
-#[derive(PartialEq, Debug, Default, Clone, Eq)]
-/// A layer-2 account identifier.
-pub struct L2Account(pub PublicKey);
+#[derive(PartialEq, Debug, Clone, Eq)]
+/// A layer-2 account identifier. Abstracts over the supported signature
+/// schemes so that ed25519- and EVM-origin clients can both take part in a
+/// channel. The `Secp256k1` variant identifies a participant by its 20-byte
+/// EVM address (derived via Keccak-256), recovered from a recoverable signature
+/// over the Keccak-256 digest of the message at verification time; Bitcoin-style
+/// addresses and message digests are not supported.
+pub enum L2Account {
+	/// An ed25519 public key.
+	Ed25519(PublicKey),
+	/// A 20-byte secp256k1 address, as used by EVM-origin clients.
+	Secp256k1([u8; 20]),
+}
+
+impl Default for L2Account {
+	fn default() -> Self {
+		L2Account::Ed25519(PublicKey::default())
+	}
+}
 
 #[derive(PartialEq, Clone, Eq)]
 // A layer-2 signature for signing Perun protocol messages.
-pub struct L2Signature(pub Signature);
+pub enum L2Signature {
+	/// An ed25519 signature.
+	Ed25519(Signature),
+	/// A 65-byte secp256k1 recoverable signature (`r || s || v`).
+	Secp256k1(ByteBuf),
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+/// The signature scheme of an [`L2Account`]/[`L2Signature`], encoded as the
+/// leading tag byte of their Candid blob representation.
+pub enum Scheme {
+	Ed25519 = 0,
+	Secp256k1 = 1,
+}
+
+impl Scheme {
+	fn from_tag(tag: u8) -> Option<Self> {
+		match tag {
+			0 => Some(Scheme::Ed25519),
+			1 => Some(Scheme::Secp256k1),
+			_ => None,
+		}
+	}
+}
 
 /// A payable layer-1 account identifier. Could be both a user or a canister.
 pub use ic_cdk::export::candid::Principal as L1Account;
@@ -74,9 +118,19 @@ pub struct State {
 	pub channel: ChannelId,
 	/// The channel's current state revision number.
 	pub version: Version,
-	/// The channel's asset allocation. Contains each participant's current
-	/// balance in the order of the channel parameters' participant list.
-	pub allocation: Vec<Amount>,
+	/// The holding canister / token principals of the assets held in the
+	/// channel, in the same order as the rows of `allocation`.
+	pub assets: Vec<L1Account>,
+	/// The channel's asset allocation matrix, indexed `[asset][participant]`.
+	/// Each row holds every participant's balance in one asset, in the order of
+	/// the channel parameters' participant list.
+	pub allocation: Vec<Vec<Amount>>,
+	/// Pending hashed-timelock conditional payments. Each entry escrows funds
+	/// out of the sender's allocation entry for its asset until the matching
+	/// preimage is revealed before its timeout, or the amount refunds on
+	/// settlement. Empty for channels that carry no in-flight conditional
+	/// transfers.
+	pub htlcs: Vec<Htlc>,
 	/// Whether the channel is finalized, i.e., no more updates can be made and
 	/// funds can be withdrawn immediately. A non-finalized channel has to be
 	/// finalized via the canister after the channel's challenge duration
@@ -84,6 +138,27 @@ pub struct State {
 	pub finalized: bool,
 }
 
+#[derive(Deserialize, CandidType, Clone, PartialEq, Eq)]
+/// A hashed-timelock conditional payment escrowed within a channel state. The
+/// `amount` is held out of the sender's allocation entry and is credited to the
+/// receiver only if a preimage hashing to `hash` is revealed before `timeout`,
+/// otherwise it refunds to the sender on settlement.
+pub struct Htlc {
+	/// The hash the claiming preimage must match under `Hash::digest`.
+	pub hash: Hash,
+	/// The asset's index into the state's `assets`/`allocation` rows.
+	pub asset: u64,
+	/// The escrowed amount, held out of the sender's allocation entry.
+	pub amount: Amount,
+	/// The escrowing participant's index in the parameters' participant list.
+	pub sender: u64,
+	/// The crediting participant's index in the parameters' participant list.
+	pub receiver: u64,
+	/// The timestamp after which the payment can no longer be claimed and
+	/// refunds to the sender.
+	pub timeout: Timestamp,
+}
+
 #[derive(Deserialize, CandidType, Default)]
 /// A channel state, signed by all participants.
 pub struct FullySignedState {
@@ -106,6 +181,10 @@ pub struct RegisteredState {
 	/// The challenge timeout after which the currently registered state becomes
 	/// available for withdrawing. Ignored for finalized channels.
 	pub timeout: Timestamp,
+	/// The hashes of all HTLC preimages that have already been claimed against
+	/// this registered state, so that the same conditional payment cannot be
+	/// resolved twice.
+	pub claimed: Vec<Hash>,
 }
 
 #[derive(Deserialize, CandidType, Clone)]
@@ -118,6 +197,37 @@ pub struct WithdrawalRequest {
 	pub receiver: L1Account,
 }
 
+#[derive(Deserialize, CandidType, Clone)]
+/// A participant's pre-authorization for a third party (a watchtower) to submit
+/// disputes or refutations on its behalf while it is offline. Signed by the
+/// participant's layer-2 key; the canister ties the authorization to the
+/// delegate's layer-1 caller principal and caps the states it may push.
+pub struct DisputeDelegation {
+	/// The channel the delegation applies to.
+	pub channel: ChannelId,
+	/// The layer-1 principal permitted to submit on the participant's behalf.
+	pub delegate: L1Account,
+	/// The highest state version the delegate is allowed to submit.
+	pub max_version: Version,
+}
+
+#[derive(Deserialize, CandidType, Clone)]
+/// A co-signed acknowledgment that every channel state below `min_version` has
+/// been revoked, binding the acknowledgment to a single channel. Submitted
+/// alongside a refutation to penalize a participant who registered a state that
+/// both parties had already superseded.
+pub struct RevocationAck {
+	/// The channel the revocation applies to.
+	pub channel: ChannelId,
+	/// The lowest version that is still considered valid. Any registered state
+	/// below this version has been revoked.
+	pub min_version: Version,
+	/// The index of the participant who revoked the acknowledged states and is
+	/// penalized if they register one below `min_version`. Co-signed, so the
+	/// penalized party itself attests to being the cheater.
+	pub cheater: u64,
+}
+
 #[derive(PartialEq, Clone, Default, Deserialize, Eq, Hash, CandidType)]
 /// Identifies the funds belonging to a certain layer 2 identity within a
 /// certain channel.
@@ -126,6 +236,9 @@ pub struct Funding {
 	pub channel: ChannelId,
 	/// The funds' owner's layer-2 identity within the channel.
 	pub participant: L2Account,
+	/// The asset to withdraw, as an index into the state's `assets`. `None`
+	/// withdraws the participant's balance across all assets.
+	pub asset: Option<u64>,
 }
 
 // Hash
@@ -191,10 +304,25 @@ impl<'de> Deserialize<'de> for L2Account {
 		D: Deserializer<'de>,
 	{
 		let bytes = ByteBuf::deserialize(deserializer)?;
-		let pk = PublicKey::from_bytes(bytes.as_slice())
-			.ok()
-			.ok_or(D::Error::invalid_length(bytes.len(), &"public key"))?;
-		Ok(L2Account(pk))
+		let (&tag, body) = bytes
+			.split_first()
+			.ok_or(D::Error::invalid_length(0, &"account scheme tag"))?;
+		match Scheme::from_tag(tag)
+			.ok_or(D::Error::custom("unknown L2 account signature scheme"))?
+		{
+			Scheme::Ed25519 => {
+				let pk = PublicKey::from_bytes(body)
+					.ok()
+					.ok_or(D::Error::invalid_length(body.len(), &"public key"))?;
+				Ok(L2Account::Ed25519(pk))
+			}
+			Scheme::Secp256k1 => {
+				let addr: [u8; 20] = body
+					.try_into()
+					.map_err(|_| D::Error::invalid_length(body.len(), &"20-byte address"))?;
+				Ok(L2Account::Secp256k1(addr))
+			}
+		}
 	}
 }
 
@@ -207,16 +335,78 @@ impl CandidType for L2Account {
 	where
 		S: Serializer,
 	{
-		serializer.serialize_blob(&self.0.to_bytes())
+		let mut blob = Vec::with_capacity(33);
+		match self {
+			L2Account::Ed25519(pk) => {
+				blob.push(Scheme::Ed25519 as u8);
+				blob.extend_from_slice(&pk.to_bytes());
+			}
+			L2Account::Secp256k1(addr) => {
+				blob.push(Scheme::Secp256k1 as u8);
+				blob.extend_from_slice(addr);
+			}
+		}
+		serializer.serialize_blob(&blob)
 	}
 }
 
 impl std::hash::Hash for L2Account {
 	fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-		self.0.to_bytes().hash(state);
+		match self {
+			L2Account::Ed25519(pk) => {
+				(Scheme::Ed25519 as u8).hash(state);
+				pk.to_bytes().hash(state);
+			}
+			L2Account::Secp256k1(addr) => {
+				(Scheme::Secp256k1 as u8).hash(state);
+				addr.hash(state);
+			}
+		}
 	}
 }
 
+impl L2Account {
+	/// Verifies a signature over `msg` against this account, dispatching on the
+	/// account's signature scheme. For the secp256k1 variant the public key is
+	/// recovered from the 65-byte recoverable signature and its derived 20-byte
+	/// address is checked against the stated account.
+	pub fn verify(&self, msg: &[u8], sig: &L2Signature) -> CanisterResult<()> {
+		match (self, sig) {
+			(L2Account::Ed25519(pk), L2Signature::Ed25519(sig)) => {
+				pk.verify_strict(msg, sig).ok().ok_or(Error::Authentication)
+			}
+			(L2Account::Secp256k1(addr), L2Signature::Secp256k1(sig)) => {
+				use k256::ecdsa::recoverable;
+				use sha3::{Digest as _, Keccak256};
+				let sig = recoverable::Signature::try_from(sig.as_slice())
+					.ok()
+					.ok_or(Error::Authentication)?;
+				// EVM-origin clients sign the Keccak-256 digest of the message,
+				// the same hash used to derive their address below, so recover
+				// from that digest.
+				let key = sig
+					.recover_verifying_key_from_digest(Keccak256::new().chain(msg))
+					.ok()
+					.ok_or(Error::Authentication)?;
+				require!(&eth_address(&key) == addr, Authentication);
+				Ok(())
+			}
+			_ => Err(Error::Authentication),
+		}
+	}
+}
+
+/// Derives the 20-byte EVM address of a secp256k1 public key as the last 20
+/// bytes of the Keccak-256 hash of its uncompressed encoding.
+fn eth_address(key: &k256::ecdsa::VerifyingKey) -> [u8; 20] {
+	use sha3::Digest as _;
+	let point = key.to_encoded_point(false);
+	let hash = sha3::Keccak256::digest(&point.as_bytes()[1..]);
+	let mut addr = [0u8; 20];
+	addr.copy_from_slice(&hash[12..]);
+	addr
+}
+
 // L2Signature
 
 impl<'de> Deserialize<'de> for L2Signature {
@@ -225,9 +415,25 @@ impl<'de> Deserialize<'de> for L2Signature {
 		D: Deserializer<'de>,
 	{
 		let bytes = ByteBuf::deserialize(deserializer)?;
-		let sig = Signature::try_from(bytes.as_slice())
-			.map_err(|_| D::Error::invalid_length(bytes.len(), &"signature"))?;
-		Ok(L2Signature(sig))
+		let (&tag, body) = bytes
+			.split_first()
+			.ok_or(D::Error::invalid_length(0, &"signature scheme tag"))?;
+		match Scheme::from_tag(tag)
+			.ok_or(D::Error::custom("unknown L2 signature scheme"))?
+		{
+			Scheme::Ed25519 => {
+				let sig = Signature::try_from(body)
+					.map_err(|_| D::Error::invalid_length(body.len(), &"signature"))?;
+				Ok(L2Signature::Ed25519(sig))
+			}
+			Scheme::Secp256k1 => {
+				require!(
+					body.len() == 65,
+					D::Error::invalid_length(body.len(), &"recoverable signature")
+				);
+				Ok(L2Signature::Secp256k1(ByteBuf::from(body.to_vec())))
+			}
+		}
 	}
 }
 
@@ -240,7 +446,18 @@ impl CandidType for L2Signature {
 	where
 		S: Serializer,
 	{
-		serializer.serialize_blob(&self.0.to_bytes())
+		let mut blob = Vec::new();
+		match self {
+			L2Signature::Ed25519(sig) => {
+				blob.push(Scheme::Ed25519 as u8);
+				blob.extend_from_slice(&sig.to_bytes());
+			}
+			L2Signature::Secp256k1(sig) => {
+				blob.push(Scheme::Secp256k1 as u8);
+				blob.extend_from_slice(sig);
+			}
+		}
+		serializer.serialize_blob(&blob)
 	}
 }
 
@@ -249,16 +466,27 @@ impl CandidType for L2Signature {
 impl State {
 	pub fn validate_sig(&self, sig: &L2Signature, pk: &L2Account) -> CanisterResult<()> {
 		let enc = Encode!(self).expect("encoding state");
-		pk.0.verify_strict(&enc, &sig.0)
-			.ok()
-			.ok_or(Error::Authentication)
+		pk.verify(&enc, sig)
 	}
 
-	/// Calculates the total funds in a channel's state.
-	pub fn total(&self) -> Amount {
-		self.allocation
+	/// Calculates the total funds in a channel's state per asset, including the
+	/// amounts escrowed in pending HTLCs, so that conservation against each
+	/// asset's deposits holds independently regardless of whether conditional
+	/// payments are in flight. The returned vector is indexed by asset, in the
+	/// order of `assets`.
+	pub fn total(&self) -> Vec<Amount> {
+		let mut totals: Vec<Amount> = self
+			.allocation
 			.iter()
-			.fold(Amount::default(), |x, y| x + y.clone())
+			.map(|row| row.iter().fold(Amount::default(), |x, y| x + y.clone()))
+			.collect();
+		for h in self.htlcs.iter() {
+			let asset = h.asset as usize;
+			if asset < totals.len() {
+				totals[asset] = totals[asset].clone() + h.amount.clone();
+			}
+		}
+		totals
 	}
 
 	/// Channels that are in their initial state may not yet be fully funded,
@@ -267,6 +495,24 @@ impl State {
 	pub fn may_be_underfunded(&self) -> bool {
 		self.version == 0 && !self.finalized
 	}
+
+	/// Checks, per asset, whether the state's outcome is covered by the supplied
+	/// deposits, which are indexed by asset in the order of `assets`. Each
+	/// asset's column total must not exceed its deposit; a shortfall in any
+	/// single asset is tolerated only while the channel `may_be_underfunded`, so
+	/// a channel under-funded in one asset can still be registered without
+	/// letting a finalized or updated state over-allocate any asset.
+	pub fn is_funded(&self, deposits: &[Amount]) -> bool {
+		let totals = self.total();
+		if totals.len() != deposits.len() {
+			return false;
+		}
+		let underfundable = self.may_be_underfunded();
+		totals
+			.iter()
+			.zip(deposits.iter())
+			.all(|(total, deposit)| total <= deposit || underfundable)
+	}
 }
 
 // Params
@@ -285,7 +531,17 @@ impl FullySignedState {
 	pub fn validate(&self, params: &Params) -> CanisterResult<()> {
 		require!(self.state.channel == params.id(), InvalidInput);
 		require!(self.sigs.len() == params.participants.len(), InvalidInput);
-		require!(self.sigs.len() == self.state.allocation.len(), InvalidInput);
+		require!(self.state.allocation.len() == self.state.assets.len(), InvalidInput);
+
+		for row in self.state.allocation.iter() {
+			require!(row.len() == params.participants.len(), InvalidInput);
+		}
+
+		for htlc in self.state.htlcs.iter() {
+			require!((htlc.asset as usize) < self.state.assets.len(), InvalidInput);
+			require!((htlc.sender as usize) < params.participants.len(), InvalidInput);
+			require!((htlc.receiver as usize) < params.participants.len(), InvalidInput);
+		}
 
 		for (i, pk) in params.participants.iter().enumerate() {
 			self.state.validate_sig(&self.sigs[i], pk)?;
@@ -308,6 +564,7 @@ impl RegisteredState {
 		Ok(Self {
 			state: state.state,
 			timeout: Default::default(),
+			claimed: Default::default(),
 		})
 	}
 
@@ -320,12 +577,134 @@ impl RegisteredState {
 		Ok(Self {
 			state: state.state,
 			timeout: now + params.challenge_duration,
+			claimed: Default::default(),
 		})
 	}
 
 	pub fn settled(&self, now: Timestamp) -> bool {
 		self.state.finalized || now >= self.timeout
 	}
+
+	/// Overturns a stale disputed state with a strictly newer, fully-signed one.
+	/// The new state's signatures are re-validated against `params`, the stored
+	/// state is replaced, and the challenge timeout is reset. Finalized states
+	/// can never be refuted, and refutation is rejected once the channel has
+	/// settled.
+	pub fn refute(
+		&mut self,
+		new: FullySignedState,
+		params: &Params,
+		now: Timestamp,
+	) -> CanisterResult<()> {
+		require!(!self.settled(now), InvalidInput);
+		require!(new.state.version > self.state.version, InvalidInput);
+		new.validate(params)?;
+		self.state = new.state;
+		self.timeout = now + params.challenge_duration;
+		Ok(())
+	}
+
+	/// Refutes a stale disputed state and, if the currently registered state was
+	/// revoked by the co-signed `ack`, penalizes the participant that `ack` names
+	/// as cheater: after the new state is stored, their balance in it is
+	/// reallocated to the honest counterparties.
+	pub fn refute_with_penalty(
+		&mut self,
+		new: FullySignedState,
+		ack: RevocationAck,
+		ack_sigs: &[L2Signature],
+		params: &Params,
+		now: Timestamp,
+	) -> CanisterResult<()> {
+		let penalize = self.state.version < ack.min_version;
+		let cheater = ack.cheater as usize;
+		if penalize {
+			require!(ack.channel == params.id(), InvalidInput);
+			require!(cheater < params.participants.len(), InvalidInput);
+			// At least one honest recipient is required, otherwise the forfeited
+			// balance would have nowhere to go and be destroyed.
+			require!(params.participants.len() >= 2, InvalidInput);
+			ack.validate(params, ack_sigs)?;
+		}
+
+		self.refute(new, params, now)?;
+
+		if penalize {
+			// Reclaim the cheater's HTLC escrow so their full balance is
+			// forfeited, not just the liquid allocation. Both count toward their
+			// deposits via `total()`, so leaving the escrow would let the cheater
+			// keep funds they had locked in HTLCs they sent.
+			let mut escrowed = vec![Amount::default(); self.state.allocation.len()];
+			self.state.htlcs.retain(|h| {
+				if h.sender as usize == cheater {
+					let asset = h.asset as usize;
+					if asset < escrowed.len() {
+						escrowed[asset] = escrowed[asset].clone() + h.amount.clone();
+					}
+					false
+				} else {
+					true
+				}
+			});
+
+			// Forfeit the cheater's balance in every asset, distributing it
+			// evenly across the honest participants and conserving each asset's
+			// column total. Any indivisible remainder goes to the first honest
+			// participant.
+			let others = Amount::from(params.participants.len() - 1);
+			for (asset, row) in self.state.allocation.iter_mut().enumerate() {
+				let forfeit = core::mem::take(&mut row[cheater]) + escrowed[asset].clone();
+				let share = forfeit.clone() / others.clone();
+				let mut remainder = forfeit % others.clone();
+				for (i, bal) in row.iter_mut().enumerate() {
+					if i == cheater {
+						continue;
+					}
+					*bal = bal.clone() + share.clone() + core::mem::take(&mut remainder);
+				}
+			}
+		}
+		Ok(())
+	}
+
+	/// Resolves a pending HTLC by revealing its preimage. The matching payment
+	/// is credited to its receiver and removed from escrow. Only succeeds on a
+	/// non-finalized, disputed state whose challenge timeout has already passed,
+	/// when the reveal happens before the HTLC's own timeout, and when the
+	/// preimage has not already been claimed against this state.
+	pub fn claim_htlc(&mut self, preimage: &ByteBuf, now: Timestamp) -> CanisterResult<()> {
+		require!(!self.state.finalized, InvalidInput);
+		require!(now >= self.timeout, NotFinalized);
+
+		let hash = Hash::digest(preimage);
+		require!(!self.claimed.contains(&hash), InvalidInput);
+
+		let idx = self
+			.state
+			.htlcs
+			.iter()
+			.position(|h| h.hash == hash)
+			.ok_or(Error::InvalidInput)?;
+		require!(now < self.state.htlcs[idx].timeout, InvalidInput);
+
+		let htlc = self.state.htlcs.remove(idx);
+		let (asset, receiver) = (htlc.asset as usize, htlc.receiver as usize);
+		self.state.allocation[asset][receiver] =
+			self.state.allocation[asset][receiver].clone() + htlc.amount;
+		self.claimed.push(hash);
+		Ok(())
+	}
+
+	/// Refunds all still-locked HTLCs back to their senders. Called when the
+	/// channel settles so that conditional payments that were never claimed
+	/// return to the escrowing participant.
+	pub fn refund_htlcs(&mut self) {
+		for htlc in self.state.htlcs.drain(..) {
+			let (asset, sender) = (htlc.asset as usize, htlc.sender as usize);
+			self.state.allocation[asset][sender] =
+				self.state.allocation[asset][sender].clone() + htlc.amount;
+		}
+	}
 }
 
 // WithdrawalRequest
@@ -337,22 +716,61 @@ impl WithdrawalRequest {
 
 	pub fn validate_sig(&self, sig: &L2Signature) -> CanisterResult<()> {
 		let enc = Encode!(self).expect("encoding withdrawal request");
-		self.funding
-			.participant
-			.0
-			.verify_strict(&enc, &sig.0)
-			.ok()
-			.ok_or(Error::Authentication)
+		self.funding.participant.verify(&enc, sig)
+	}
+}
+
+// DisputeDelegation
+
+impl DisputeDelegation {
+	/// Validates that the delegation was authorized by `participant`'s layer-2
+	/// key over its Candid encoding, exactly as withdrawal requests are
+	/// authorized.
+	pub fn validate_sig(
+		&self,
+		sig: &L2Signature,
+		participant: &L2Account,
+	) -> CanisterResult<()> {
+		let enc = Encode!(self).expect("encoding dispute delegation");
+		participant.verify(&enc, sig)
+	}
+
+	/// Checks that `caller` is the blessed delegate and that the submitted
+	/// `state` belongs to the delegated channel and does not exceed the
+	/// delegation's version ceiling, so a watchtower can only push states the
+	/// owner blessed.
+	pub fn authorize(&self, caller: &L1Account, state: &State) -> CanisterResult<()> {
+		require!(caller == &self.delegate, Authentication);
+		require!(state.channel == self.channel, InvalidInput);
+		require!(state.version <= self.max_version, InvalidInput);
+		Ok(())
+	}
+}
+
+// RevocationAck
+
+impl RevocationAck {
+	/// Checks that the acknowledgment is co-signed by every channel participant
+	/// over its Candid encoding, in the order of the parameters' participant
+	/// list.
+	pub fn validate(&self, params: &Params, sigs: &[L2Signature]) -> CanisterResult<()> {
+		require!(sigs.len() == params.participants.len(), InvalidInput);
+		let enc = Encode!(self).expect("encoding revocation acknowledgment");
+		for (i, pk) in params.participants.iter().enumerate() {
+			pk.verify(&enc, &sigs[i])?;
+		}
+		Ok(())
 	}
 }
 
 // Funding
 
 impl Funding {
-	pub fn new(channel: ChannelId, participant: L2Account) -> Self {
+	pub fn new(channel: ChannelId, participant: L2Account, asset: Option<u64>) -> Self {
 		Self {
 			channel,
 			participant,
+			asset,
 		}
 	}
 }